@@ -1,8 +1,167 @@
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
+use std::io;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::thread;
+
+// Scale factor of the fixed-point representation: 10^4, i.e. four decimal places.
+const SCALE: i64 = 10_000;
+
+/// Exact monetary amount stored as a count of ten-thousandths (four decimal
+/// places). Wrapping an `i64` keeps the ledger penny-exact and reproducible,
+/// unlike the binary floats that drift when many transactions are summed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Amount(i64);
+
+impl Add for Amount {
+    type Output = Amount;
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+// Render the exact scaled value with exactly four decimals, e.g. `1.5000`.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let magnitude = self.0.abs();
+        write!(f, "{}{}.{:04}", sign, magnitude / SCALE, magnitude % SCALE)
+    }
+}
+
+// Parse a decimal string such as `1.5` or `-100.2345` into the scaled integer,
+// keeping at most four fractional digits.
+impl FromStr for Amount {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Amount, String> {
+        let s = s.trim();
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int_part, frac_part) = match digits.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (digits, ""),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(format!("invalid amount: {:?}", s));
+        }
+        let int_value: i64 = if int_part.is_empty() {
+            0
+        } else {
+            int_part
+                .parse()
+                .map_err(|_| format!("invalid amount: {:?}", s))?
+        };
+        // Pad or truncate the fractional part to exactly four digits.
+        let mut frac_digits = String::from(frac_part);
+        frac_digits.truncate(4);
+        while frac_digits.len() < 4 {
+            frac_digits.push('0');
+        }
+        let frac_value: i64 = frac_digits
+            .parse()
+            .map_err(|_| format!("invalid amount: {:?}", s))?;
+        let value = int_value * SCALE + frac_value;
+        Ok(Amount(if negative { -value } else { value }))
+    }
+}
+
+// The CSV amount column arrives as a decimal string; parse it straight into the
+// exact fixed-point type instead of going through a lossy float.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Amount>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = Option::<String>::deserialize(deserializer)?;
+    match raw {
+        Some(ref s) if !s.is_empty() => {
+            Amount::from_str(s).map(Some).map_err(serde::de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reasons an individual transaction can be rejected. Surfacing these instead
+/// of silently swallowing them gives callers actionable diagnostics and makes
+/// the engine testable at the per-operation level.
+#[derive(Debug, PartialEq, Eq)]
+enum LedgerError {
+    NotEnoughFunds,
+    UnknownTx(u16, u32),
+    AlreadyDisputed,
+    NotDisputed,
+    FrozenAccount,
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::NotEnoughFunds => write!(f, "insufficient available funds"),
+            LedgerError::UnknownTx(client, tx) => {
+                write!(f, "unknown transaction {} for client {}", tx, client)
+            }
+            LedgerError::AlreadyDisputed => write!(f, "transaction is already disputed"),
+            LedgerError::NotDisputed => write!(f, "transaction is not under dispute"),
+            LedgerError::FrozenAccount => write!(f, "account is locked"),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+/// Lifecycle of a logged transaction. Only three transitions are legal —
+/// `Processed -> Disputed`, `Disputed -> Resolved`, and `Disputed ->
+/// ChargedBack` — so a resolved or charged-back transaction can never be
+/// disputed again.
+#[derive(Debug, PartialEq, Eq)]
+enum TxState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// Kind of an amount-bearing transaction we keep in the per-account log. The
+/// kind decides which way a dispute moves funds: a deposit dispute holds money
+/// the client received, while a withdrawal dispute reverses money that left the
+/// account, so the held-funds sign is mirrored between the two.
+#[derive(Debug, PartialEq, Eq)]
+enum TxKind {
+    Deposit,
+    Withdrawal,
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
@@ -22,147 +181,320 @@ struct OffChainTransaction {
     client: u16,
     #[serde(rename = "tx")]
     id: u32,
-    amount: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    amount: Option<Amount>,
 }
 
 //Struct for an account
 #[derive(Debug)]
 struct Account {
-    available: f64,
-    held: f64,
+    available: Amount,
+    held: Amount,
     locked: bool,
-    transactions: HashMap<u32, (f64, bool)>,
+    transactions: HashMap<u32, (Amount, TxState, TxKind)>,
+}
+
+// Signed amount a dispute adds to `held` (and removes from `available`). A
+// deposit holds the received funds (`+amount`); a withdrawal mirrors this
+// (`-amount`), pulling the withdrawn funds back into `available` while `held`
+// goes negative, so resolve/chargeback act in the right direction for each.
+fn held_delta(amount: Amount, kind: &TxKind) -> Amount {
+    match kind {
+        TxKind::Deposit => amount,
+        TxKind::Withdrawal => -amount,
+    }
 }
 
-fn process_transaction(account: &mut Account, transaction: &OffChainTransaction) {
+fn process_transaction(
+    account: &mut Account,
+    transaction: &OffChainTransaction,
+) -> Result<(), LedgerError> {
+    // a locked account rejects every further operation
+    if account.locked {
+        return Err(LedgerError::FrozenAccount);
+    }
+
     // process transaction
     match transaction.transaction_type {
         TransactionType::Deposit => {
             // add transaction amount and log transaction
             if let Some(amount) = transaction.amount {
                 account.available += amount;
-                account.transactions.insert(transaction.id, (amount, false));
+                account
+                    .transactions
+                    .insert(transaction.id, (amount, TxState::Processed, TxKind::Deposit));
             }
+            Ok(())
         }
         TransactionType::Withdrawal => {
-            // removes transaction amount if funds are enough
+            // removes transaction amount if funds are enough and logs it so it can be disputed
             if let Some(amount) = transaction.amount {
-                if account.available >= amount {
-                    account.available -= amount;
+                if account.available < amount {
+                    return Err(LedgerError::NotEnoughFunds);
                 }
+                account.available -= amount;
+                account.transactions.insert(
+                    transaction.id,
+                    (amount, TxState::Processed, TxKind::Withdrawal),
+                );
             }
+            Ok(())
         }
         TransactionType::Dispute => {
-            // move disputed amount to held balance and flags transaction
-            if let Some((amount, disputed)) = account.transactions.get_mut(&transaction.id) {
-                if !*disputed {
-                    account.available -= *amount;
-                    account.held += *amount;
-                    *disputed = true;
+            // hold the disputed funds; only a processed tx may be disputed. The sign of
+            // `delta` mirrors the two cases: a deposit moves `amount` from available into
+            // held, a withdrawal moves it the opposite way (held goes negative while the
+            // withdrawn funds flow back into available).
+            match account.transactions.get_mut(&transaction.id) {
+                Some((amount, state, kind)) => {
+                    if *state != TxState::Processed {
+                        return Err(LedgerError::AlreadyDisputed);
+                    }
+                    let delta = held_delta(*amount, kind);
+                    account.available -= delta;
+                    account.held += delta;
+                    *state = TxState::Disputed;
+                    Ok(())
                 }
+                None => Err(LedgerError::UnknownTx(transaction.client, transaction.id)),
             }
         }
         TransactionType::Resolve => {
-            // reverts transaction amount from held to available and removes the flag from transaction
-            if let Some((amount, disputed)) = account.transactions.get_mut(&transaction.id) {
-                if *disputed {
-                    account.available += *amount;
-                    account.held -= *amount;
-                    *disputed = false;
+            // release the held funds back; only a disputed tx may be resolved
+            match account.transactions.get_mut(&transaction.id) {
+                Some((amount, state, kind)) => {
+                    if *state != TxState::Disputed {
+                        return Err(LedgerError::NotDisputed);
+                    }
+                    let delta = held_delta(*amount, kind);
+                    account.available += delta;
+                    account.held -= delta;
+                    *state = TxState::Resolved;
+                    Ok(())
                 }
+                None => Err(LedgerError::UnknownTx(transaction.client, transaction.id)),
             }
         }
         TransactionType::Chargeback => {
-            // removes dispute amount from held and locks the account
-            if let Some((amount, disputed)) = account.transactions.get_mut(&transaction.id) {
-                if *disputed {
-                    account.held -= *amount;
+            // settle the held funds and lock; only a disputed tx may charge back. For a
+            // deposit this removes money from the account, for a withdrawal it returns it.
+            match account.transactions.get_mut(&transaction.id) {
+                Some((amount, state, kind)) => {
+                    if *state != TxState::Disputed {
+                        return Err(LedgerError::NotDisputed);
+                    }
+                    let delta = held_delta(*amount, kind);
+                    account.held -= delta;
                     account.locked = true;
-                    *disputed = false;
+                    *state = TxState::ChargedBack;
+                    Ok(())
                 }
+                None => Err(LedgerError::UnknownTx(transaction.client, transaction.id)),
             }
         }
     }
 }
 
-pub fn process_transaction_file(file: std::fs::File) -> String {
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(file);
+// Number of worker lanes to shard the stream across. Honours the
+// `PAYMENTS_WORKERS` env var and otherwise falls back to the available
+// parallelism of the machine.
+fn worker_count() -> usize {
+    env::var("PAYMENTS_WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+}
 
-    let iter_reader = reader.deserialize::<OffChainTransaction>();
+// Process one lane's worth of transactions into its own account map. A lane may
+// hold several clients, but every transaction of a given client lands in the same
+// lane, so disputes and chargebacks always find their referenced `tx` inside that
+// lane and no shared state or locking between lanes is needed.
+fn process_shard(transactions: Vec<OffChainTransaction>) -> HashMap<u16, Account> {
     let mut accounts: HashMap<u16, Account> = HashMap::new();
 
-    // iterate csv file line by line
-    for item in iter_reader {
-        if let Ok(transaction) = item {
-            // check if account exists, if not, it adds a new one
-            if !accounts.contains_key(&transaction.client) {
-                accounts.insert(
-                    transaction.client,
-                    Account {
-                        available: 0.0,
-                        held: 0.0,
-                        locked: false,
-                        transactions: HashMap::new(),
-                    },
-                );
-            }
-            let account = accounts.get_mut(&transaction.client).unwrap();
+    for transaction in transactions {
+        // check if account exists, if not, it adds a new one
+        let account = accounts.entry(transaction.client).or_insert_with(|| Account {
+            available: Amount::default(),
+            held: Amount::default(),
+            locked: false,
+            transactions: HashMap::new(),
+        });
 
-            // process transaction if not locked
-            if !account.locked {
-                process_transaction(account, &transaction)
+        // process transaction, logging the specific failure per offending row
+        if let Err(err) = process_transaction(account, &transaction) {
+            eprintln!(
+                "Skipping client {} tx {}: {}",
+                transaction.client, transaction.id, err
+            );
+        }
+    }
+
+    accounts
+}
+
+pub fn process_transaction_file<R: io::Read, W: io::Write>(
+    input: R,
+    output: W,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+
+    // partition transactions into worker lanes keyed by client id so each client
+    // is always handled by the same lane, preserving per-client ordering
+    let worker_count = worker_count();
+    let mut lanes: Vec<Vec<OffChainTransaction>> =
+        (0..worker_count).map(|_| Vec::new()).collect();
+
+    for item in reader.deserialize::<OffChainTransaction>() {
+        match item {
+            Ok(transaction) => {
+                let lane = (transaction.client as usize) % worker_count;
+                lanes[lane].push(transaction);
             }
-        } else {
             // log row that could not be deserialized
-            eprintln!("Skipping invalid row: {:?}", item);
+            Err(err) => eprintln!("Skipping invalid row: {:?}", err),
         }
     }
 
-    // convert the keys to vector so that I can sort them in a predictale way
-    // to validate the output using cargo test
-    // generates a warning for keys being mutable
-    let mut keys: Vec<_> = accounts.keys().cloned().collect();
+    // process each lane concurrently, then merge the disjoint account maps
+    let mut handles = Vec::with_capacity(lanes.len());
+    for lane in lanes {
+        handles.push(thread::spawn(move || process_shard(lane)));
+    }
 
-    #[cfg(test)]
-    keys.sort();
+    // collecting the merged lanes into a BTreeMap keys clients in sorted order,
+    // so the dump is deterministic in release builds too
+    let mut accounts: BTreeMap<u16, Account> = BTreeMap::new();
+    for handle in handles {
+        let shard = handle.join().expect("worker lane panicked");
+        accounts.extend(shard);
+    }
 
-    // print final csv to cli
-    let mut output = String::from("client,available,held,total,locked");
+    // write the final csv through csv::Writer so fields are quoted/escaped correctly
+    let mut writer = csv::Writer::from_writer(output);
+    writer.write_record(["client", "available", "held", "total", "locked"])?;
 
-    for client in keys {
-        let data = accounts.get(&client).unwrap();
-        let total = data.held + data.available;
-        output = format!(
-            "{}\n{},{:.4},{:.4},{:.4},{}",
-            output, client, data.available, data.held, total, data.locked
-        );
+    for (client, data) in &accounts {
+        let total = data.available + data.held;
+        writer.write_record([
+            client.to_string(),
+            data.available.to_string(),
+            data.held.to_string(),
+            total.to_string(),
+            data.locked.to_string(),
+        ])?;
     }
-    output = format!("{}\n", output);
-    return output;
+
+    writer.flush()?;
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    let file = File::open(&args[1])?;
-    let output = process_transaction_file(file);
-    print!("{}", output);
+    // with a path argument read that file, otherwise consume the CSV from stdin
+    // so the engine can be dropped into a pipe (`cat txns.csv | engine`)
+    let stdout = io::stdout();
+    if args.len() > 1 {
+        process_transaction_file(File::open(&args[1])?, stdout.lock())?;
+    } else {
+        let stdin = io::stdin();
+        process_transaction_file(stdin.lock(), stdout.lock())?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::process_transaction_file;
+    use crate::{
+        process_transaction, process_transaction_file, Account, Amount, LedgerError,
+        OffChainTransaction, TransactionType,
+    };
+    use std::collections::HashMap;
     use std::fs::File;
 
+    fn empty_account() -> Account {
+        Account {
+            available: Amount::default(),
+            held: Amount::default(),
+            locked: false,
+            transactions: HashMap::new(),
+        }
+    }
+
+    fn tx(transaction_type: TransactionType, id: u32, amount: Option<f64>) -> OffChainTransaction {
+        OffChainTransaction {
+            transaction_type,
+            client: 1,
+            id,
+            amount: amount.map(|a| Amount((a * 10_000.0).round() as i64)),
+        }
+    }
+
+    #[test]
+    fn disputing_a_deposit_moves_funds_into_held() {
+        let mut account = empty_account();
+        process_transaction(&mut account, &tx(TransactionType::Deposit, 1, Some(10.0))).unwrap();
+        process_transaction(&mut account, &tx(TransactionType::Dispute, 1, None)).unwrap();
+        assert_eq!(account.available, Amount(0));
+        assert_eq!(account.held, Amount(100_000));
+    }
+
+    #[test]
+    fn disputing_a_withdrawal_mirrors_the_held_sign() {
+        let mut account = empty_account();
+        process_transaction(&mut account, &tx(TransactionType::Deposit, 1, Some(10.0))).unwrap();
+        process_transaction(&mut account, &tx(TransactionType::Withdrawal, 2, Some(4.0))).unwrap();
+        // after the withdrawal available is 6.0
+        process_transaction(&mut account, &tx(TransactionType::Dispute, 2, None)).unwrap();
+        // withdrawn funds flow back into available while held goes negative
+        assert_eq!(account.available, Amount(100_000));
+        assert_eq!(account.held, Amount(-40_000));
+        // charging the withdrawal back returns the funds and locks the account
+        process_transaction(&mut account, &tx(TransactionType::Chargeback, 2, None)).unwrap();
+        assert_eq!(account.available, Amount(100_000));
+        assert_eq!(account.held, Amount(0));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn disputing_a_spent_deposit_drives_available_negative() {
+        let mut account = empty_account();
+        process_transaction(&mut account, &tx(TransactionType::Deposit, 1, Some(10.0))).unwrap();
+        process_transaction(&mut account, &tx(TransactionType::Withdrawal, 2, Some(10.0))).unwrap();
+        // the deposit's funds are already gone, so holding them drives available negative
+        process_transaction(&mut account, &tx(TransactionType::Dispute, 1, None)).unwrap();
+        assert_eq!(account.available, Amount(-100_000));
+        assert_eq!(account.held, Amount(100_000));
+    }
+
+    #[test]
+    fn disputing_an_unknown_tx_is_reported() {
+        let mut account = empty_account();
+        let result = process_transaction(&mut account, &tx(TransactionType::Dispute, 7, None));
+        assert_eq!(result, Err(LedgerError::UnknownTx(1, 7)));
+    }
+
+    // run the engine over a fixture file and capture the csv it writes to the sink
+    fn run(path: &str) -> String {
+        let file = File::open(path).unwrap();
+        let mut output = Vec::new();
+        process_transaction_file(file, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
     #[test]
     fn default() {
         let correct_output = "client,available,held,total,locked
 1,1.5000,0.0000,1.5000,false
 2,2.0000,0.0000,2.0000,false\n";
-        let file = File::open("./test_files/transactions_1.csv").unwrap();
-        assert_eq!(process_transaction_file(file), correct_output);
+        assert_eq!(run("./test_files/transactions_1.csv"), correct_output);
     }
 
     #[test]
@@ -170,8 +502,7 @@ mod tests {
         let correct_output = "client,available,held,total,locked
 1,70.0000,0.0000,70.0000,true
 2,300.0000,0.0000,300.0000,false\n";
-        let file = File::open("./test_files/transactions_2.csv").unwrap();
-        assert_eq!(process_transaction_file(file), correct_output);
+        assert_eq!(run("./test_files/transactions_2.csv"), correct_output);
     }
 
     #[test]
@@ -179,8 +510,7 @@ mod tests {
         let correct_output = "client,available,held,total,locked
 1,20.0000,0.0000,20.0000,true
 2,-100.0000,0.0000,-100.0000,true\n";
-        let file = File::open("./test_files/transactions_3.csv").unwrap();
-        assert_eq!(process_transaction_file(file), correct_output);
+        assert_eq!(run("./test_files/transactions_3.csv"), correct_output);
     }
 
     #[test]
@@ -188,7 +518,6 @@ mod tests {
         let correct_output = "client,available,held,total,locked
 1,50.0000,0.0000,50.0000,false
 2,0.0000,0.0000,0.0000,false\n";
-        let file = File::open("./test_files/transactions_4.csv").unwrap();
-        assert_eq!(process_transaction_file(file), correct_output);
+        assert_eq!(run("./test_files/transactions_4.csv"), correct_output);
     }
 }